@@ -1,98 +1,188 @@
-use std::cell::RefCell;
+use std::cell::Cell;
 use std::marker::PhantomData;
 use std::ops::Deref;
 use std::ptr::NonNull;
 
-#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
-pub struct GcFlag(u8);
+/// Tri-color marking state: White is unreached, Gray is reached but not
+/// yet scanned, Black is reached and scanned.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GcColor {
+    White,
+    Gray,
+    Black,
+}
 
 pub trait Traceable: 'static {
-    fn trace(&self, flag: GcFlag);
+    /// Shade reachable children through `gray`. Must not recurse into
+    /// their own `trace`; that's `GcAllocator::step`'s job.
+    fn trace(&self, gray: &mut GrayQueue);
 }
 
 pub trait GcAllocator {
     fn alloc<T: Traceable>(&mut self, value: T) -> GcRef<T>;
 
-    fn mark<T: Traceable>(&self, gc_ref: GcRef<T>);
+    /// Mark a root: White -> Gray, enqueued for scanning by `step`.
+    fn mark<T: Traceable>(&mut self, gc_ref: &GcRef<T>);
+
+    /// Scan up to `budget` gray objects: shade children gray, then color
+    /// scanned objects black. Call repeatedly until the gray worklist is
+    /// empty, then `sweep`.
+    fn step(&mut self, budget: usize);
+
     fn sweep(&mut self);
+
+    /// Dijkstra write barrier: call whenever `parent` is mutated to
+    /// point at `child`, re-shading a Black `parent` Gray if `child` is
+    /// still White so it isn't missed.
+    fn write_barrier<P: Traceable, C: Traceable>(
+        &mut self,
+        parent: &GcRef<P>,
+        child: &GcRef<C>,
+    );
 }
 
+#[derive(Default)]
 pub struct SimpleGcAllocator {
-    flag: GcFlag,
-    pub(crate) values: Vec<NonNull<dyn ValueTrait>>,
+    values: Vec<NonNull<dyn ValueTrait>>,
+    gray: Vec<NonNull<dyn ValueTrait>>,
 }
 
-impl Default for SimpleGcAllocator {
-    fn default() -> SimpleGcAllocator {
-        SimpleGcAllocator {
-            flag: GcFlag(1),
-            values: Vec::new(),
-        }
+impl SimpleGcAllocator {
+    /// True if a cycle is in progress (the gray worklist isn't empty).
+    /// Callers interleaving GC work with other work use this to decide
+    /// whether to mark a fresh set of roots or keep stepping.
+    pub fn is_marking(&self) -> bool {
+        !self.gray.is_empty()
     }
 }
 
 trait ValueTrait {
-    fn dealloc(&mut self, flag: GcFlag) -> bool;
+    fn color(&self) -> GcColor;
+    fn set_color(&self, color: GcColor);
+    fn shade_children(&self, gray: &mut Vec<NonNull<dyn ValueTrait>>);
+    fn free(&mut self);
+}
+
+/// Handle passed to `Traceable::trace` so a value can shade its children
+/// gray without recursing into them itself.
+pub struct GrayQueue<'a> {
+    list: &'a mut Vec<NonNull<dyn ValueTrait>>,
+}
+
+impl<'a> GrayQueue<'a> {
+    pub fn shade<T: Traceable>(&mut self, gc_ref: &GcRef<T>) {
+        let gc_value = gc_ref.inner();
+        if gc_value.color.get() == GcColor::White {
+            gc_value.color.set(GcColor::Gray);
+            self.list.push(gc_ref.erase());
+        }
+    }
 }
 
 impl GcAllocator for SimpleGcAllocator {
     fn alloc<T: Traceable>(&mut self, value: T) -> GcRef<T> {
-        // Allocate value and GcValue wrapper
+        // Allocate with a cycle already in progress: the mutator could
+        // stash this straight into a place mark_roots won't revisit
+        // until the *next* cycle (e.g. pushed onto a thread's stack),
+        // with no write barrier to catch it since it has no black
+        // parent. Allocate it Gray and enqueue it so this cycle scans
+        // it like any other root instead of mistaking it for garbage.
+        let marking = self.is_marking();
         let gc_value = GcValue {
             value: Some(value),
-            flag: RefCell::new(GcFlag(0)),
+            color: Cell::new(if marking {
+                GcColor::Gray
+            } else {
+                GcColor::White
+            }),
         };
         let ptr: &mut GcValue<T> = Box::leak(Box::new(gc_value));
+        let gc_ref = GcRef {
+            ptr: ptr.into(),
+            phantom: PhantomData,
+        };
 
         // Store reference in vec
-        let ptr_t: NonNull<dyn ValueTrait> = {
-            let r: &dyn ValueTrait = ptr;
-            r.into()
-        };
-        self.values.push(ptr_t.into());
+        self.values.push(gc_ref.erase());
+        if marking {
+            self.gray.push(gc_ref.erase());
+        }
 
-        // Return GcRef
-        GcRef {
-            ptr: ptr.into(),
-            phantom: PhantomData,
+        gc_ref
+    }
+
+    fn mark<T: Traceable>(&mut self, gc_ref: &GcRef<T>) {
+        let gc_value = gc_ref.inner();
+        if gc_value.color.get() == GcColor::White {
+            gc_value.color.set(GcColor::Gray);
+            self.gray.push(gc_ref.erase());
         }
     }
 
-    fn mark<T: Traceable>(&self, gc_ref: GcRef<T>) {
-        gc_ref.trace_ref(self.flag);
+    fn step(&mut self, budget: usize) {
+        for _ in 0..budget {
+            let ptr = match self.gray.pop() {
+                Some(ptr) => ptr,
+                None => break,
+            };
+            let v: &dyn ValueTrait = unsafe { &*ptr.as_ptr() };
+            v.shade_children(&mut self.gray);
+            v.set_color(GcColor::Black);
+        }
     }
 
     fn sweep(&mut self) {
-        // Sweep
-        let flag = self.flag;
         self.values.retain(|v| {
             let v: &mut dyn ValueTrait = unsafe { &mut *v.as_ptr() };
-            let deleted = v.dealloc(flag);
-            !deleted
+            if v.color() == GcColor::White {
+                v.free();
+                false
+            } else {
+                // Survivors go back to White for the next cycle.
+                v.set_color(GcColor::White);
+                true
+            }
         });
+    }
 
-        // Switch flag
-        self.flag = GcFlag(match self.flag.0 {
-            1 => 2,
-            _ => 1,
-        });
+    fn write_barrier<P: Traceable, C: Traceable>(
+        &mut self,
+        parent: &GcRef<P>,
+        child: &GcRef<C>,
+    ) {
+        let parent_value = parent.inner();
+        if parent_value.color.get() == GcColor::Black
+            && child.inner().color.get() == GcColor::White
+        {
+            parent_value.color.set(GcColor::Gray);
+            self.gray.push(parent.erase());
+        }
     }
 }
 
 pub struct GcValue<T: Traceable> {
     value: Option<T>,
-    flag: RefCell<GcFlag>,
+    color: Cell<GcColor>,
 }
 
 impl<T: Traceable> ValueTrait for GcValue<T> {
-    fn dealloc(&mut self, flag: GcFlag) -> bool {
-        if *self.flag.borrow() != flag {
-            self.value.take();
-            true
-        } else {
-            false
+    fn color(&self) -> GcColor {
+        self.color.get()
+    }
+
+    fn set_color(&self, color: GcColor) {
+        self.color.set(color);
+    }
+
+    fn shade_children(&self, gray: &mut Vec<NonNull<dyn ValueTrait>>) {
+        if let Some(ref value) = self.value {
+            value.trace(&mut GrayQueue { list: gray });
         }
     }
+
+    fn free(&mut self) {
+        self.value.take();
+    }
 }
 
 pub struct GcRef<T: Traceable> {
@@ -110,21 +200,13 @@ impl<T: Traceable> Clone for GcRef<T> {
 }
 
 impl<T: Traceable> GcRef<T> {
-    pub fn trace_ref(&self, flag: GcFlag) {
-        let gc_value = self.inner();
-        let mut flag_ref = gc_value.flag.borrow_mut();
-        if *flag_ref != flag {
-            *flag_ref = flag;
-            if let Some(ref value) = gc_value.value {
-                value.trace(flag);
-            }
-        }
+    fn inner(&self) -> &GcValue<T> {
+        unsafe { &*self.ptr.as_ptr() }
     }
 
-    fn inner(&self) -> &GcValue<T> {
-        unsafe {
-            &*self.ptr.as_ptr()
-        }
+    fn erase(&self) -> NonNull<dyn ValueTrait> {
+        let r: &dyn ValueTrait = self.inner();
+        r.into()
     }
 }
 
@@ -140,22 +222,38 @@ impl<T: Traceable> Deref for GcRef<T> {
     }
 }
 
+// Convenience impl so leaf values can be stored behind a `GcRef` without
+// every embedder having to write an empty `trace` by hand.
+impl Traceable for String {
+    fn trace(&self, _gray: &mut GrayQueue) {}
+}
+
 #[cfg(test)]
 mod tests {
-    use super::{GcAllocator, GcFlag, GcRef, SimpleGcAllocator, Traceable};
+    use super::{GcAllocator, GrayQueue, SimpleGcAllocator, Traceable};
+    use std::cell::RefCell;
 
     enum Value {
         Integer(i32),
-        Array(Vec<GcRef<Value>>),
+        Array(Vec<super::GcRef<Value>>),
+        // Interior-mutable slot, standing in for the VM's mutable
+        // Table/Cell values, so a test can mutate an already-scanned
+        // parent to point at a white child.
+        Cell(RefCell<Option<super::GcRef<Value>>>),
     }
 
     impl Traceable for Value {
-        fn trace(&self, flag: GcFlag) {
+        fn trace(&self, gray: &mut GrayQueue) {
             match self {
                 Value::Integer(_) => {}
                 Value::Array(v) => {
                     for elem in v {
-                        elem.trace_ref(flag);
+                        gray.shade(elem);
+                    }
+                }
+                Value::Cell(c) => {
+                    if let Some(elem) = &*c.borrow() {
+                        gray.shade(elem);
                     }
                 }
             }
@@ -172,9 +270,10 @@ mod tests {
         let arr1 = gc.alloc(Value::Array(vec![int1.clone()]));
         let _arr2 = gc.alloc(Value::Array(vec![int1.clone(), int2.clone()]));
 
-        // Mark & sweep
-        gc.mark(arr1.clone());
-        gc.mark(int3.clone());
+        // Mark, scan to completion, then sweep
+        gc.mark(&arr1);
+        gc.mark(&int3);
+        gc.step(10);
         gc.sweep();
 
         assert_eq!(gc.values.len(), 3);
@@ -189,4 +288,52 @@ mod tests {
                 .collect::<Vec<_>>(),
         );
     }
+
+    #[test]
+    fn test_gc_incremental_step_budget() {
+        // A chain long enough that one unit of budget can't finish marking
+        // it, exercising the resumable nature of `step`: as long as we
+        // keep stepping until the gray worklist is drained before
+        // sweeping, the whole chain survives regardless of how the work
+        // was split up.
+        let mut gc: SimpleGcAllocator = Default::default();
+        let leaf = gc.alloc(Value::Integer(0));
+        let mid = gc.alloc(Value::Array(vec![leaf.clone()]));
+        let root = gc.alloc(Value::Array(vec![mid.clone()]));
+
+        gc.mark(&root);
+        gc.step(1); // scans root, shades mid gray
+        gc.step(1); // scans mid, shades leaf gray
+        gc.step(1); // scans leaf
+        gc.sweep();
+
+        assert_eq!(gc.values.len(), 3);
+    }
+
+    #[test]
+    fn test_write_barrier_keeps_mutated_child_alive() {
+        // Drive `cell` to Black with nothing in it yet, then store a
+        // fresh White `leaf` into it through the write barrier. Without
+        // the barrier re-shading `cell` Gray, `step` would never revisit
+        // it and `sweep` would reclaim `leaf` out from under a reference
+        // that's still live.
+        let mut gc: SimpleGcAllocator = Default::default();
+        let cell = gc.alloc(Value::Cell(RefCell::new(None)));
+
+        gc.mark(&cell);
+        gc.step(10); // scans cell (empty), colors it Black
+
+        let leaf = gc.alloc(Value::Integer(0));
+        *match &*cell {
+            Value::Cell(c) => c,
+            _ => unreachable!(),
+        }
+        .borrow_mut() = Some(leaf.clone());
+        gc.write_barrier(&cell, &leaf);
+
+        gc.step(10); // re-scans cell (now Gray), shades leaf
+        gc.sweep();
+
+        assert_eq!(gc.values.len(), 2);
+    }
 }
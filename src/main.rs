@@ -1,11 +1,12 @@
-use num_derive::FromPrimitive;
-use num_traits::FromPrimitive;
+use gc::{GcAllocator, GcRef, GrayQueue, SimpleGcAllocator, Traceable};
+use std::cell::RefCell;
 use std::collections::HashMap;
+use std::env;
 use std::error::Error;
 use std::fmt;
 use std::fs::File;
 use std::io::Read;
-use std::rc::Rc;
+use std::rc::{Rc, Weak};
 
 #[derive(Debug)]
 pub enum CompileError {
@@ -42,207 +43,865 @@ impl fmt::Display for ExecError {
 
 impl Error for ExecError {}
 
-#[derive(FromPrimitive)]
-pub enum Instruction {
-    Return,
-    Call,
-    LoadConstant,
-    LoadCode,
-    MakeFunction,
-    LoadGlobal,
-    SetGlobal,
-    GetAttr,
-    SetAttr,
-    Pop,
+/// Default `VirtualMachine::max_stack_depth`, chosen to be generous for
+/// the sample program while still catching runaway recursion in
+/// untrusted bytecode.
+const DEFAULT_MAX_STACK_DEPTH: usize = 1024;
+
+/// Gray objects `VirtualMachine::step_gc` scans per instruction.
+const GC_STEP_BUDGET: usize = 8;
+
+/// Outcome of a fuel-bounded `VirtualMachine::execute` call.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExecResult {
+    /// The program hit a top-level return; `thread` has nothing left to
+    /// run.
+    Finished,
+    /// `count` instructions ran out before the program finished.
+    /// `thread.instr`/`thread.stack` are left intact, so calling
+    /// `execute` again with more fuel resumes exactly where this call
+    /// left off.
+    Yielded,
+}
+
+/// Operand kinds an instruction's bytes can decode into. Shared between
+/// the bounds-checked fetcher and the disassembler so neither can drift
+/// out of sync with the other about an opcode's layout.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OperandKind {
+    /// A bare count/argument-number byte, printed as-is.
+    Count,
+    /// Index into the current code's `constants`.
+    ConstantIndex,
+    /// Index into the current code's `codes`.
+    CodeIndex,
+}
+
+/// Declares the instruction set once: each opcode's discriminant (its
+/// position in this list) together with its operand layout. Everything
+/// else — the `Instruction` enum, opcode decoding, operand bounds
+/// checking, instruction length, and the disassembler — is derived from
+/// this single spec, the way holey-bytes derives its encode/decode
+/// tables from one macro invocation, instead of being hand-written (and
+/// hand-kept-in-sync) per opcode.
+macro_rules! instructions {
+    ($($name:ident [ $($kind:expr),* ]),+ $(,)?) => {
+        #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+        pub enum Instruction {
+            $($name,)+
+        }
+
+        impl Instruction {
+            fn from_opcode(opcode: u8) -> Option<Instruction> {
+                const TABLE: &[Instruction] = &[$(Instruction::$name,)+];
+                TABLE.get(opcode as usize).copied()
+            }
+
+            fn name(self) -> &'static str {
+                match self {
+                    $(Instruction::$name => stringify!($name),)+
+                }
+            }
+
+            /// Operand kinds this instruction's bytes decode into, in
+            /// order.
+            fn operand_kinds(self) -> &'static [OperandKind] {
+                match self {
+                    $(Instruction::$name => &[$($kind),*],)+
+                }
+            }
+        }
+    };
+}
+
+instructions! {
+    Return[],
+    Call[OperandKind::Count],
+    LoadConstant[OperandKind::ConstantIndex],
+    LoadCode[OperandKind::CodeIndex],
+    MakeFunction[OperandKind::Count],
+    LoadGlobal[OperandKind::ConstantIndex],
+    SetGlobal[OperandKind::ConstantIndex],
+    GetAttr[],
+    SetAttr[],
+    Pop[OperandKind::Count],
+    LoadUpvalue[OperandKind::Count],
+    SetUpvalue[OperandKind::Count],
+}
+
+/// Decoded operand bytes for one instruction, one entry per kind
+/// returned by `Instruction::operand_kinds`.
+pub struct Operands(Vec<u8>);
+
+impl Operands {
+    fn get(&self, i: usize) -> u8 {
+        self.0[i]
+    }
+}
+
+/// Bounds-checked operand fetch generated from the instruction spec
+/// above: never reads past `bytes`, returning
+/// `ExecError::InvalidInstruction` on a truncated stream instead of
+/// panicking.
+fn fetch_operands(
+    instr: Instruction,
+    bytes: &[u8],
+    pos: usize,
+) -> Result<Operands, ExecError> {
+    let kinds = instr.operand_kinds();
+    if pos + kinds.len() > bytes.len() {
+        return Err(ExecError::InvalidInstruction);
+    }
+    Ok(Operands(bytes[pos..pos + kinds.len()].to_vec()))
+}
+
+/// Total length in bytes of `instr`, opcode byte included.
+fn instr_len(instr: Instruction) -> usize {
+    1 + instr.operand_kinds().len()
+}
+
+/// Push `value` onto `stack`, enforcing `max_stack_depth` instead of
+/// letting it grow unbounded: every place `execute` grows the stack
+/// (arguments, return frames, pushed results, ...) goes through this so
+/// runaway or adversarial bytecode hits `ExecError::StackFull` rather
+/// than exhausting host memory.
+fn push_checked(
+    stack: &mut Vec<Value>,
+    max_stack_depth: usize,
+    value: Value,
+) -> Result<(), ExecError> {
+    if stack.len() >= max_stack_depth {
+        return Err(ExecError::StackFull);
+    }
+    stack.push(value);
+    Ok(())
+}
+
+/// Render `code`'s instructions as human-readable text, resolving
+/// constant-index operands against `code.constants`, then disassembling
+/// every entry of `code.codes` (indented, under a `-- code N --`
+/// header). Code-index operands are printed as bare numbers, and a
+/// `Code` held in a constant is shown only as `<code>`, not
+/// disassembled.
+pub fn disassemble(code: &Code) -> String {
+    let mut out = String::new();
+    disassemble_into(code, 0, &mut out);
+    out
+}
+
+fn disassemble_into(code: &Code, indent: usize, out: &mut String) {
+    let pad = "    ".repeat(indent);
+    let mut pos = 0;
+    while pos < code.instrs.len() {
+        let opcode = match Instruction::from_opcode(code.instrs[pos]) {
+            Some(opcode) => opcode,
+            None => {
+                out.push_str(&format!(
+                    "{}{:04} <invalid opcode {}>\n",
+                    pad, pos, code.instrs[pos]
+                ));
+                pos += 1;
+                continue;
+            }
+        };
+
+        let operands = match fetch_operands(opcode, &code.instrs, pos + 1) {
+            Ok(operands) => operands,
+            Err(_) => {
+                out.push_str(&format!(
+                    "{}{:04} {} <truncated>\n",
+                    pad,
+                    pos,
+                    opcode.name()
+                ));
+                break;
+            }
+        };
+
+        out.push_str(&format!("{}{:04} {}", pad, pos, opcode.name()));
+        for (i, kind) in opcode.operand_kinds().iter().enumerate() {
+            let value = operands.get(i);
+            out.push_str(&format!(" {}", value));
+            if *kind == OperandKind::ConstantIndex {
+                if let Some(constant) = code.constants.get(value as usize) {
+                    out.push_str(&format!(" ; {}", describe_value(constant)));
+                }
+            }
+        }
+        out.push('\n');
+
+        pos += instr_len(opcode);
+    }
+
+    for (i, nested) in code.codes.iter().enumerate() {
+        out.push_str(&format!("{}-- code {} --\n", pad, i));
+        disassemble_into(nested, indent + 1, out);
+    }
+}
+
+fn describe_value(value: &Value) -> String {
+    match value {
+        Value::String(s) => format!("{:?}", &**s),
+        Value::Integer(i) => i.to_string(),
+        Value::Nil => "nil".to_owned(),
+        Value::Code(_) => "<code>".to_owned(),
+        Value::Function(_) => "<function>".to_owned(),
+        Value::Object(_) => "<object>".to_owned(),
+        Value::Native(_) => "<native>".to_owned(),
+        Value::Upvalue(_) => "<upvalue>".to_owned(),
+        Value::Userdata(_) => "<userdata>".to_owned(),
+    }
 }
 
 pub struct Code {
+    /// Number of upvalue cells a `MakeFunction`/`Call` using this code
+    /// expects to receive, captured from the enclosing scope.
     upvalues: usize,
     params: usize,
     constants: Vec<Value>,
     instrs: Vec<u8>,
-    codes: Vec<Rc<Code>>,
+    codes: Vec<GcRef<Code>>,
+}
+
+impl Traceable for Code {
+    fn trace(&self, gray: &mut GrayQueue) {
+        for constant in &self.constants {
+            constant.trace(gray);
+        }
+        for code in &self.codes {
+            gray.shade(code);
+        }
+    }
 }
 
 pub struct Function {
-    code: Rc<Code>,
-    upvalues: Vec<Value>,
+    code: GcRef<Code>,
+    upvalues: Vec<GcRef<Cell>>,
+}
+
+impl Traceable for Function {
+    fn trace(&self, gray: &mut GrayQueue) {
+        gray.shade(&self.code);
+        for upvalue in &self.upvalues {
+            gray.shade(upvalue);
+        }
+    }
+}
+
+/// A mutable, GC-managed box holding one captured `Value`, shared by
+/// every closure whose capture list points at the same cell. `RefCell`
+/// rather than `std::cell::Cell` since `Value` isn't `Copy`.
+pub struct Cell(RefCell<Value>);
+
+impl Cell {
+    pub fn new(value: Value) -> Cell {
+        Cell(RefCell::new(value))
+    }
+
+    pub fn get(&self) -> Value {
+        self.0.borrow().clone()
+    }
+
+    pub fn set(&self, value: Value) {
+        *self.0.borrow_mut() = value;
+    }
+}
+
+impl Traceable for Cell {
+    fn trace(&self, gray: &mut GrayQueue) {
+        self.0.borrow().trace(gray);
+    }
+}
+
+/// A plain object: a bag of named attributes, backing `GetAttr`/`SetAttr`
+/// and method lookup. Values are GC-traced; keys are plain owned
+/// `Rc<String>` clones (not interned or shared), so they're left out of
+/// tracing. Wrapped in a `RefCell` since a `Value::Object` is a shared
+/// `GcRef<Table>` and attributes must be settable through it.
+pub struct Table {
+    attrs: RefCell<HashMap<Rc<String>, Value>>,
+}
+
+impl Default for Table {
+    fn default() -> Table {
+        Table::new()
+    }
+}
+
+impl Table {
+    pub fn new() -> Table {
+        Table {
+            attrs: RefCell::new(HashMap::new()),
+        }
+    }
+
+    pub fn get(&self, key: &String) -> Option<Value> {
+        self.attrs.borrow().get(key).cloned()
+    }
+
+    pub fn set(&self, key: Rc<String>, value: Value) {
+        self.attrs.borrow_mut().insert(key, value);
+    }
+}
+
+impl Traceable for Table {
+    fn trace(&self, gray: &mut GrayQueue) {
+        for value in self.attrs.borrow().values() {
+            value.trace(gray);
+        }
+    }
+}
+
+/// A Rust function exposed to the language. Kept out of the GC since a
+/// boxed closure's captures aren't introspectable for tracing; natives
+/// are expected to live for the lifetime of the `VirtualMachine` that
+/// registered them, so a plain `Rc` is enough.
+pub struct Native {
+    func: Box<
+        dyn Fn(&mut VirtualMachine, &mut [Value]) -> Result<Value, ExecError>,
+    >,
+}
+
+/// Converts a popped `Value` into a native argument, the way gluon's
+/// `Getable` converts a VM `Value` into a Rust type.
+pub trait FromValue: Sized {
+    fn from_value(
+        vm: &mut VirtualMachine,
+        value: Value,
+    ) -> Result<Self, ExecError>;
+}
+
+/// Converts a native return value back into a `Value`, the way gluon's
+/// `Pushable` pushes a Rust type onto the VM stack.
+pub trait IntoValue {
+    fn into_value(self, vm: &mut VirtualMachine) -> Value;
+}
+
+impl FromValue for i32 {
+    fn from_value(
+        _vm: &mut VirtualMachine,
+        value: Value,
+    ) -> Result<i32, ExecError> {
+        match value {
+            Value::Integer(i) => Ok(i),
+            _ => Err(ExecError::InvalidInstruction),
+        }
+    }
+}
+
+impl IntoValue for i32 {
+    fn into_value(self, _vm: &mut VirtualMachine) -> Value {
+        Value::Integer(self)
+    }
+}
+
+impl FromValue for String {
+    fn from_value(
+        _vm: &mut VirtualMachine,
+        value: Value,
+    ) -> Result<String, ExecError> {
+        match value {
+            Value::String(s) => Ok((*s).clone()),
+            _ => Err(ExecError::InvalidInstruction),
+        }
+    }
+}
+
+impl IntoValue for String {
+    fn into_value(self, vm: &mut VirtualMachine) -> Value {
+        Value::String(vm.alloc(self))
+    }
+}
+
+impl FromValue for bool {
+    fn from_value(
+        _vm: &mut VirtualMachine,
+        value: Value,
+    ) -> Result<bool, ExecError> {
+        match value {
+            Value::Integer(0) => Ok(false),
+            Value::Integer(_) => Ok(true),
+            _ => Err(ExecError::InvalidInstruction),
+        }
+    }
+}
+
+impl IntoValue for bool {
+    fn into_value(self, _vm: &mut VirtualMachine) -> Value {
+        Value::Integer(if self { 1 } else { 0 })
+    }
+}
+
+impl FromValue for () {
+    fn from_value(
+        _vm: &mut VirtualMachine,
+        _value: Value,
+    ) -> Result<(), ExecError> {
+        Ok(())
+    }
+}
+
+impl IntoValue for () {
+    fn into_value(self, _vm: &mut VirtualMachine) -> Value {
+        Value::Nil
+    }
 }
 
-pub fn compile_text<R: Read>(file: R) -> Result<Code, CompileError> {
-    // TODO: Compile text into bytecode
-    Ok(Code {
+/// Marshals an ordinary Rust closure into a `Native`, checking its arity
+/// against the argument window and converting each argument through
+/// `FromValue`. `Args` is a phantom tuple of argument types, letting one
+/// closure type satisfy several arities without conflicting impls.
+pub trait IntoNative<Args> {
+    fn into_native(self) -> Rc<Native>;
+}
+
+macro_rules! impl_into_native {
+    ($($arg:ident),*) => {
+        impl<FN, $($arg,)* R> IntoNative<($($arg,)*)> for FN
+        where
+            FN: Fn($($arg),*) -> R + 'static,
+            $($arg: FromValue,)*
+            R: IntoValue,
+        {
+            #[allow(non_snake_case)]
+            fn into_native(self) -> Rc<Native> {
+                Rc::new(Native {
+                    func: Box::new(
+                        move |vm: &mut VirtualMachine,
+                              args: &mut [Value]| {
+                            #[allow(unused_mut, unused_variables)]
+                            let mut iter = args.iter_mut();
+                            $(
+                                let $arg = match iter.next() {
+                                    Some(slot) => $arg::from_value(
+                                        vm,
+                                        std::mem::replace(slot, Value::Nil),
+                                    )?,
+                                    None => {
+                                        return Err(ExecError::StackEmpty)
+                                    }
+                                };
+                            )*
+                            Ok(self($($arg),*).into_value(vm))
+                        },
+                    ),
+                })
+            }
+        }
+    };
+}
+
+impl_into_native!();
+impl_into_native!(A);
+impl_into_native!(A, B);
+impl_into_native!(A, B, C);
+
+pub fn compile_text<R: Read>(
+    file: R,
+    vm: &mut VirtualMachine,
+) -> Result<GcRef<Code>, CompileError> {
+    // TODO: Compile text into bytecode. The closures below stand in for
+    // what a real compiler would need to emit ahead of a nested function
+    // literal's MakeFunction: the callee's Code pushed first, then one
+    // LoadConstant of a Value::Upvalue per captured cell on top of it.
+    let _ = file;
+
+    // A shared upvalue cell, captured by two nested closures: one that
+    // writes it (via SetUpvalue) and one that publishes it to a global
+    // (via LoadUpvalue), so MakeFunction's capture list and both upvalue
+    // opcodes actually run when this program is executed.
+    let counter = vm.alloc(Cell::new(Value::Integer(0)));
+    let count_name = Value::String(vm.alloc("count".to_owned()));
+
+    let set_count_code = vm.alloc(Code {
+        upvalues: 1,
+        params: 0,
+        constants: vec![Value::Integer(5)],
+        instrs: vec![
+            Instruction::LoadConstant as u8,
+            0, // 5
+            Instruction::SetUpvalue as u8,
+            0, // counter
+        ],
+        codes: vec![],
+    });
+
+    let publish_count_code = vm.alloc(Code {
+        upvalues: 1,
+        params: 0,
+        constants: vec![count_name],
+        instrs: vec![
+            Instruction::LoadUpvalue as u8,
+            0, // counter
+            Instruction::SetGlobal as u8,
+            0, // "count"
+        ],
+        codes: vec![],
+    });
+
+    Ok(vm.alloc(Code {
         upvalues: 0,
         params: 0,
-        constants: vec![Value::String(Rc::new("main".to_owned()))],
+        constants: vec![
+            Value::Upvalue(counter.clone()),
+            Value::Upvalue(counter),
+        ],
         instrs: vec![
+            // set the shared cell through the closure that captured it
+            Instruction::LoadCode as u8,
+            0, // Code 0 = set_count_code
+            Instruction::LoadConstant as u8,
+            0, // Upvalue(counter)
+            Instruction::MakeFunction as u8,
+            1, // 1 upvalue
+            Instruction::Call as u8,
+            0, // 0 arguments
+            Instruction::Pop as u8,
+            1, // drop the set_count function value Call left behind
+            // publish it through the closure that shares the same cell
             Instruction::LoadCode as u8,
-            0, // Code 0 = main
-            // Stack: <code>
+            1, // Code 1 = publish_count_code
+            Instruction::LoadConstant as u8,
+            1, // Upvalue(counter)
             Instruction::MakeFunction as u8,
-            0, // 0 upvalues
-            // Stack: <func main>
-            Instruction::SetGlobal as u8, 0, // const 0 = "main"
+            1, // 1 upvalue
+            Instruction::Call as u8,
+            0, // 0 arguments
+            Instruction::Pop as u8,
+            1, // drop the publish_count function value Call left behind
         ],
-        codes: vec![Rc::new(Code {
-            upvalues: 0,
-            params: 0,
-            constants: vec![
-                Value::Integer(4),
-                Value::String(Rc::new("Child".to_owned())),
-                Value::String(Rc::new("greet".to_owned())),
-            ],
-            instrs: vec![
-                Instruction::LoadGlobal as u8,
-                1,
-                Instruction::LoadConstant as u8,
-                0,
-                // Stack: <Child> 4
-                Instruction::Call as u8,
-                1, // 1 argument
-                // Stack: c
-                Instruction::LoadConstant as u8,
-                2, // "greet"
-                Instruction::GetAttr as u8,
-                // Stack: c <c.greet>
-                Instruction::Call as u8,
-                0, // 0 arguments
-                // Stack: c nil
-                Instruction::Pop as u8,
-                2,
-            ],
-            codes: vec![],
-        })],
-    })
+        codes: vec![set_count_code, publish_count_code],
+    }))
 }
 
 #[derive(Clone)]
 pub enum Value {
-    String(Rc<String>),
+    String(GcRef<String>),
     Integer(i32),
     Nil,
-    Code(Rc<Code>),
-    Function(Rc<Function>),
+    Code(GcRef<Code>),
+    Function(GcRef<Function>),
+    Object(GcRef<Table>),
+    Native(Rc<Native>),
+    Upvalue(GcRef<Cell>),
     Userdata(usize),
 }
 
+impl Value {
+    fn trace(&self, gray: &mut GrayQueue) {
+        match self {
+            Value::String(s) => gray.shade(s),
+            Value::Code(c) => gray.shade(c),
+            Value::Function(f) => gray.shade(f),
+            Value::Object(o) => gray.shade(o),
+            Value::Upvalue(u) => gray.shade(u),
+            Value::Integer(_)
+            | Value::Nil
+            | Value::Native(_)
+            | Value::Userdata(_) => {}
+        }
+    }
+
+    /// Like `trace`, but used to mark a root directly rather than shade a
+    /// child found while scanning another value.
+    fn trace_root(&self, gc: &mut SimpleGcAllocator) {
+        match self {
+            Value::String(s) => gc.mark(s),
+            Value::Code(c) => gc.mark(c),
+            Value::Function(f) => gc.mark(f),
+            Value::Object(o) => gc.mark(o),
+            Value::Upvalue(u) => gc.mark(u),
+            Value::Integer(_)
+            | Value::Nil
+            | Value::Native(_)
+            | Value::Userdata(_) => {}
+        }
+    }
+}
+
 pub struct VirtualMachine {
     globals: HashMap<String, Value>,
+    gc: SimpleGcAllocator,
+    max_stack_depth: usize,
+    /// Every `Thread` handed out by `load` and still alive, so GC roots
+    /// cover all of them, not just whichever one is currently executing.
+    /// Weak so a dropped `Thread` just falls out of the next sweep
+    /// instead of being kept alive by this registry.
+    threads: Vec<Weak<RefCell<Thread>>>,
 }
 
 impl VirtualMachine {
     pub fn new() -> VirtualMachine {
         VirtualMachine {
             globals: HashMap::new(),
+            gc: SimpleGcAllocator::default(),
+            max_stack_depth: DEFAULT_MAX_STACK_DEPTH,
+            threads: Vec::new(),
+        }
+    }
+
+    /// Set the maximum depth `Thread::stack` is allowed to grow to
+    /// before `execute` returns `ExecError::StackFull`, for sandboxing
+    /// untrusted bytecode.
+    pub fn set_max_stack_depth(&mut self, max_stack_depth: usize) {
+        self.max_stack_depth = max_stack_depth;
+    }
+
+    pub fn alloc<T: Traceable>(&mut self, value: T) -> GcRef<T> {
+        self.gc.alloc(value)
+    }
+
+    /// Apply the Dijkstra write barrier before storing `value` into
+    /// `parent` — a `Table`'s attributes or an upvalue `Cell` — so a
+    /// `parent` mutated after being scanned black can't hide a white
+    /// child from the collector.
+    fn write_barrier_value<P: Traceable>(
+        &mut self,
+        parent: &GcRef<P>,
+        value: &Value,
+    ) {
+        match value {
+            Value::String(s) => self.gc.write_barrier(parent, s),
+            Value::Code(c) => self.gc.write_barrier(parent, c),
+            Value::Function(f) => self.gc.write_barrier(parent, f),
+            Value::Object(o) => self.gc.write_barrier(parent, o),
+            Value::Upvalue(u) => self.gc.write_barrier(parent, u),
+            Value::Integer(_)
+            | Value::Nil
+            | Value::Native(_)
+            | Value::Userdata(_) => {}
         }
     }
 
-    pub fn load<'a>(&'a mut self, code: Code) -> Thread {
-        Thread {
-            code: Rc::new(code),
+    /// Install `f` as a global, automatically marshalling its arguments
+    /// and return value through `FromValue`/`IntoValue`.
+    pub fn register_fn<F, Args>(&mut self, name: &str, f: F)
+    where
+        F: IntoNative<Args>,
+    {
+        self.globals
+            .insert(name.to_owned(), Value::Native(f.into_native()));
+    }
+
+    pub fn load(&mut self, code: GcRef<Code>) -> Rc<RefCell<Thread>> {
+        let thread = Rc::new(RefCell::new(Thread {
+            code,
             instr: 0,
             stack: Vec::new(),
+            upvalues: Vec::new(),
+            call_upvalues: Vec::new(),
+        }));
+        self.threads.push(Rc::downgrade(&thread));
+        thread
+    }
+
+    /// Mark every `Value` reachable from `globals` and from every live
+    /// thread's stack, captured upvalues, and currently executing code,
+    /// not just whichever thread happens to be running right now.
+    fn mark_roots(&mut self) {
+        for value in self.globals.values() {
+            value.trace_root(&mut self.gc);
+        }
+        self.threads.retain(|thread| thread.strong_count() > 0);
+        for thread in &self.threads {
+            if let Some(thread) = thread.upgrade() {
+                let thread = thread.borrow();
+                self.gc.mark(&thread.code);
+                for value in &thread.stack {
+                    value.trace_root(&mut self.gc);
+                }
+                for cell in &thread.upvalues {
+                    self.gc.mark(cell);
+                }
+                for cells in &thread.call_upvalues {
+                    for cell in cells {
+                        self.gc.mark(cell);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Force an immediate, stop-the-world collection: mark roots, scan
+    /// to completion, then sweep. `execute` already interleaves this
+    /// incrementally per instruction; this is for a host that wants a
+    /// collection right now instead of waiting for that to catch up.
+    pub fn collect_garbage(&mut self) {
+        self.mark_roots();
+        self.gc.step(usize::MAX);
+        self.gc.sweep();
+    }
+
+    /// Advance one incremental GC step: if no cycle is in progress,
+    /// mark roots to start one; scan up to `GC_STEP_BUDGET` gray
+    /// objects; sweep once the worklist drains. Called once per
+    /// instruction from `execute`.
+    fn step_gc(&mut self) {
+        if !self.gc.is_marking() {
+            self.mark_roots();
+        }
+        self.gc.step(GC_STEP_BUDGET);
+        if !self.gc.is_marking() {
+            self.gc.sweep();
         }
     }
 
+    /// Run `thread` for up to `count` instructions (or unboundedly if
+    /// `None`), returning `ExecResult::Finished` if it reaches a
+    /// top-level return, `ExecResult::Yielded` if `count` runs out
+    /// first. On `Yielded`, `thread` is left exactly where execution
+    /// stopped and can be resumed by calling `execute` again.
     pub fn execute(
         &mut self,
-        thread: &mut Thread,
+        thread: &Rc<RefCell<Thread>>,
         mut count: Option<usize>,
-    ) -> Result<bool, ExecError> {
+    ) -> Result<ExecResult, ExecError> {
+        let max_stack_depth = self.max_stack_depth;
+
         while count.unwrap_or(1) > 0 {
-            let Thread { code, instr, stack } = thread;
+            self.step_gc();
+
+            let mut thread = thread.borrow_mut();
+            let Thread {
+                code,
+                instr,
+                stack,
+                upvalues,
+                call_upvalues,
+            } = &mut *thread;
             let code_: &Code = code;
             let Code {
-                upvalues,
+                upvalues: _,
                 params,
                 constants,
                 instrs,
                 codes,
             } = code_;
 
-            // Fetch instruction
-            let opcode = if *instr >= instrs.len() {
+            // Fetch and decode the instruction, bounds-checking its
+            // operands against the instruction stream
+            let (opcode, operands) = if *instr >= instrs.len() {
                 // No more instructions, implicit return
-                Instruction::Return
+                (Instruction::Return, Operands(Vec::new()))
             } else {
-                let opcode = instrs[*instr];
-                *instr += 1;
-
-                // Decode instruction
-                match FromPrimitive::from_u8(opcode) {
+                let opcode = match Instruction::from_opcode(instrs[*instr]) {
                     Some(c) => c,
                     None => return Err(ExecError::InvalidInstruction),
-                }
+                };
+                let operands = fetch_operands(opcode, instrs, *instr + 1)?;
+                *instr += instr_len(opcode);
+                (opcode, operands)
             };
 
             // Execute instructions
             match opcode {
-                Instruction::Return => match (stack.pop(), stack.pop()) {
-                    (Some(Value::Integer(i)), Some(Value::Code(c)))
-                        if i >= 0 =>
-                    {
-                        *instr = i as usize;
-                        *code = c;
+                Instruction::Return => {
+                    if call_upvalues.is_empty() {
+                        // No caller frame to return into: this is the
+                        // top-level thread finishing, not an error.
+                        return Ok(ExecResult::Finished);
                     }
-                    (Some(_), Some(_)) => {
-                        return Err(ExecError::InvalidInstruction);
-                    }
-                    _ => {
-                        return Err(ExecError::StackEmpty);
+                    // Call pushes Integer(instr) then Code(code), so
+                    // they come back off in the opposite order: Code on
+                    // top, Integer underneath it.
+                    match (stack.pop(), stack.pop()) {
+                        (Some(Value::Code(c)), Some(Value::Integer(i)))
+                            if i >= 0 =>
+                        {
+                            *instr = i as usize;
+                            *code = c;
+                            *upvalues =
+                                call_upvalues.pop().unwrap_or_default();
+                        }
+                        (Some(_), Some(_)) => {
+                            return Err(ExecError::InvalidInstruction);
+                        }
+                        _ => {
+                            return Err(ExecError::StackEmpty);
+                        }
                     }
-                },
+                }
                 Instruction::Call => {
                     // Function call needs the function and the arguments to be
                     // on the stack, and pushes the current instruction counter
                     // and code object before switching to the new code
 
-                    // Read operand: number of arguments on stack
-                    let nb_args = instrs[*instr] as usize;
-                    *instr += 1;
+                    // Operand: number of arguments on stack
+                    let nb_args = operands.get(0) as usize;
 
                     // Check stack
                     if stack.len() < nb_args + 1 {
                         return Err(ExecError::StackEmpty);
                     }
 
-                    // Get the function object
-                    let func = match &stack[stack.len() - 1 - nb_args] {
-                        Value::Function(f) => f.clone(),
-                        _ => return Err(ExecError::InvalidInstruction),
-                    };
-                    let func_code: &Code = &func.code;
+                    // Get the callee
+                    let callee = stack[stack.len() - 1 - nb_args].clone();
+                    match callee {
+                        Value::Function(func) => {
+                            let func_code: &Code = &func.code;
 
-                    if func_code.params > nb_args {
-                        // Set missing arguments to nil
-                        stack.reserve(func_code.params - nb_args);
-                        for _ in nb_args..func_code.params {
-                            stack.push(Value::Nil);
-                        }
-                    } else if func_code.params < nb_args {
-                        // Remove extra arguments
-                        stack.truncate(
-                            stack.len() + func_code.params - nb_args,
-                        );
-                    }
+                            if func_code.params > nb_args {
+                                // Set missing arguments to nil
+                                stack.reserve(func_code.params - nb_args);
+                                for _ in nb_args..func_code.params {
+                                    push_checked(
+                                        stack,
+                                        max_stack_depth,
+                                        Value::Nil,
+                                    )?;
+                                }
+                            } else if func_code.params < nb_args {
+                                // Remove extra arguments
+                                stack.truncate(
+                                    stack.len() + func_code.params - nb_args,
+                                );
+                            }
 
-                    if func_code.upvalues > 0 {
-                        // TODO: Deal with upvalues somehow
-                        return Err(ExecError::InvalidInstruction);
-                    }
+                            // Push the previous instruction counter and
+                            // code object
+                            push_checked(
+                                stack,
+                                max_stack_depth,
+                                Value::Integer(*instr as i32),
+                            )?;
+                            push_checked(
+                                stack,
+                                max_stack_depth,
+                                Value::Code(code.clone()),
+                            )?;
 
-                    // Push the previous instruction counter and code object
-                    stack.push(Value::Integer(*instr as i32));
-                    stack.push(Value::Code(code.clone()));
+                            // Swap in the callee's captured upvalues,
+                            // stashing ours to be restored on Return
+                            call_upvalues.push(std::mem::replace(
+                                upvalues,
+                                func.upvalues.clone(),
+                            ));
 
-                    // Switch to the new code
-                    *instr = 0;
-                    *code = func.code.clone();
+                            // Switch to the new code
+                            *instr = 0;
+                            *code = func.code.clone();
+                        }
+                        Value::Native(native) => {
+                            // Natives run synchronously on the host stack,
+                            // so there's no frame to push or code to
+                            // switch to: slice off the argument window,
+                            // call, then replace the callee and arguments
+                            // with the result.
+                            let callee_start = stack.len() - 1 - nb_args;
+                            let args_start = stack.len() - nb_args;
+                            let result = {
+                                let args = &mut stack[args_start..];
+                                (native.func)(self, args)?
+                            };
+                            stack.truncate(callee_start);
+                            push_checked(stack, max_stack_depth, result)?;
+                        }
+                        _ => return Err(ExecError::InvalidInstruction),
+                    }
                 }
                 Instruction::LoadConstant => {
-                    // Read operand: constant number
-                    let constant_idx = instrs[*instr] as usize;
-                    *instr += 1;
+                    // Operand: constant number
+                    let constant_idx = operands.get(0) as usize;
 
                     // Get constant value
                     let value = if constant_idx < code.constants.len() {
@@ -252,12 +911,11 @@ impl VirtualMachine {
                     };
 
                     // Put it on the stack
-                    stack.push(value);
+                    push_checked(stack, max_stack_depth, value)?;
                 }
                 Instruction::LoadCode => {
-                    // Read operand: code number
-                    let code_idx = instrs[*instr] as usize;
-                    *instr += 1;
+                    // Operand: code number
+                    let code_idx = operands.get(0) as usize;
 
                     // Get code
                     let code_obj = if code_idx < code.codes.len() {
@@ -267,26 +925,34 @@ impl VirtualMachine {
                     };
 
                     // Put it on the stack
-                    stack.push(Value::Code(code_obj));
+                    push_checked(
+                        stack,
+                        max_stack_depth,
+                        Value::Code(code_obj),
+                    )?;
                 }
                 Instruction::MakeFunction => {
-                    // Read operand: number of upvalues
-                    let nb_upvalues = instrs[*instr] as usize;
-                    *instr += 1;
-
-                    if nb_upvalues > 0 {
-                        // TODO: Implement upvalues
-                        return Err(ExecError::InvalidInstruction);
-                    }
+                    // Operand: number of upvalues
+                    let nb_upvalues = operands.get(0) as usize;
 
                     // Check stack
                     if stack.len() < nb_upvalues + 1 {
                         return Err(ExecError::StackEmpty);
                     }
 
-                    // Get the upvalues
-                    let func_upvalues =
+                    // Get the upvalue cells: the compiler must have
+                    // pushed one Value::Upvalue per captured cell
+                    let raw_upvalues =
                         stack.split_off(stack.len() - nb_upvalues);
+                    let mut func_upvalues = Vec::with_capacity(nb_upvalues);
+                    for value in raw_upvalues {
+                        match value {
+                            Value::Upvalue(cell) => {
+                                func_upvalues.push(cell)
+                            }
+                            _ => return Err(ExecError::InvalidInstruction),
+                        }
+                    }
 
                     // Get the code object
                     let code_obj = match stack.pop() {
@@ -294,21 +960,92 @@ impl VirtualMachine {
                         _ => return Err(ExecError::InvalidInstruction),
                     };
 
+                    // The operand must agree with how many upvalue
+                    // cells the callee's own code declares capturing.
+                    if code_obj.upvalues != nb_upvalues {
+                        return Err(ExecError::InvalidInstruction);
+                    }
+
                     // Make the function object on the stack
-                    let func = Rc::new(Function {
+                    let func = self.gc.alloc(Function {
                         code: code_obj,
                         upvalues: func_upvalues,
                     });
-                    stack.push(Value::Function(func));
+                    push_checked(
+                        stack,
+                        max_stack_depth,
+                        Value::Function(func),
+                    )?;
+                }
+                Instruction::LoadGlobal => {
+                    // Operand: constant number of the global's name
+                    let constant_idx = operands.get(0) as usize;
+
+                    let name = match constants.get(constant_idx) {
+                        Some(Value::String(s)) => s,
+                        _ => return Err(ExecError::InvalidInstruction),
+                    };
+
+                    let value = self
+                        .globals
+                        .get(&**name)
+                        .cloned()
+                        .unwrap_or(Value::Nil);
+                    push_checked(stack, max_stack_depth, value)?;
+                }
+                Instruction::SetGlobal => {
+                    // Operand: constant number of the global's name
+                    let constant_idx = operands.get(0) as usize;
+
+                    let name = match constants.get(constant_idx) {
+                        Some(Value::String(s)) => (**s).clone(),
+                        _ => return Err(ExecError::InvalidInstruction),
+                    };
+
+                    let value = match stack.pop() {
+                        Some(v) => v,
+                        None => return Err(ExecError::StackEmpty),
+                    };
+
+                    self.globals.insert(name, value);
+                }
+                Instruction::GetAttr => {
+                    let key = match stack.pop() {
+                        Some(Value::String(s)) => s,
+                        Some(_) => return Err(ExecError::InvalidInstruction),
+                        None => return Err(ExecError::StackEmpty),
+                    };
+                    let obj = match stack.pop() {
+                        Some(Value::Object(o)) => o,
+                        Some(_) => return Err(ExecError::InvalidInstruction),
+                        None => return Err(ExecError::StackEmpty),
+                    };
+
+                    let value = obj.get(&key).unwrap_or(Value::Nil);
+                    push_checked(stack, max_stack_depth, value)?;
+                }
+                Instruction::SetAttr => {
+                    let value = match stack.pop() {
+                        Some(v) => v,
+                        None => return Err(ExecError::StackEmpty),
+                    };
+                    let key = match stack.pop() {
+                        Some(Value::String(s)) => s,
+                        Some(_) => return Err(ExecError::InvalidInstruction),
+                        None => return Err(ExecError::StackEmpty),
+                    };
+                    let obj = match stack.pop() {
+                        Some(Value::Object(o)) => o,
+                        Some(_) => return Err(ExecError::InvalidInstruction),
+                        None => return Err(ExecError::StackEmpty),
+                    };
+
+                    self.write_barrier_value(&obj, &value);
+                    obj.set(Rc::new((*key).clone()), value);
                 }
-                Instruction::LoadGlobal => {}
-                Instruction::SetGlobal => {}
-                Instruction::GetAttr => {}
-                Instruction::SetAttr => {}
                 Instruction::Pop => {
-                    // Read operand: number of values to pop from stack
-                    let nb = instrs[*instr] as usize;
-                    *instr += 1;
+                    // Operand: number of values to pop from stack
+                    let nb = operands.get(0) as usize;
 
                     // Check stack
                     if stack.len() < nb {
@@ -318,6 +1055,34 @@ impl VirtualMachine {
                     // Pop
                     stack.truncate(stack.len() - nb);
                 }
+                Instruction::LoadUpvalue => {
+                    // Operand: index into the executing function's
+                    // upvalue array
+                    let idx = operands.get(0) as usize;
+
+                    let cell = match upvalues.get(idx) {
+                        Some(cell) => cell.clone(),
+                        None => return Err(ExecError::InvalidInstruction),
+                    };
+                    push_checked(stack, max_stack_depth, cell.get())?;
+                }
+                Instruction::SetUpvalue => {
+                    // Operand: index into the executing function's
+                    // upvalue array
+                    let idx = operands.get(0) as usize;
+
+                    let value = match stack.pop() {
+                        Some(v) => v,
+                        None => return Err(ExecError::StackEmpty),
+                    };
+                    match upvalues.get(idx) {
+                        Some(cell) => {
+                            self.write_barrier_value(cell, &value);
+                            cell.set(value);
+                        }
+                        None => return Err(ExecError::InvalidInstruction),
+                    }
+                }
             }
 
             match count {
@@ -326,30 +1091,392 @@ impl VirtualMachine {
             }
         }
 
-        Ok(false)
+        // `count` ran out before the program returned: out of fuel, not
+        // finished. `thread` was left in place above, so the caller can
+        // resume it with another `execute` call.
+        Ok(ExecResult::Yielded)
     }
 }
 
 pub struct Thread {
-    code: Rc<Code>,
+    code: GcRef<Code>,
     instr: usize,
     stack: Vec<Value>,
+    /// Upvalue cells captured by the currently executing function, if
+    /// any; indexed by `LoadUpvalue`/`SetUpvalue`.
+    upvalues: Vec<GcRef<Cell>>,
+    /// Upvalues of suspended callers, pushed by `Call` and popped by
+    /// `Return` in lockstep with the instruction-counter/code frame.
+    call_upvalues: Vec<Vec<GcRef<Cell>>>,
 }
 
 fn main() {
+    let dump = env::args().any(|arg| arg == "--dump");
+
     let mut vm = VirtualMachine::new();
     let file = match File::open("example.lpc") {
         Ok(f) => f,
         Err(_) => panic!("Couldn't find code"),
     };
-    let program = match compile_text(file) {
+    let program = match compile_text(file, &mut vm) {
         Ok(p) => p,
         Err(e) => panic!("Error compiling code: {}", e),
     };
-    let mut thread = vm.load(program);
-    match vm.execute(&mut thread, None) {
-        Ok(true) => {}
-        Ok(false) => panic!("Program didn't finish"),
+
+    if dump {
+        print!("{}", disassemble(&program));
+        return;
+    }
+
+    let thread = vm.load(program);
+    match vm.execute(&thread, None) {
+        Ok(ExecResult::Finished) => {}
+        // Unbounded fuel (`None`) never yields; a yield here would mean
+        // `execute`'s fuel accounting is broken.
+        Ok(ExecResult::Yielded) => {
+            panic!("Program yielded with no fuel limit set")
+        }
         Err(e) => panic!("Error running program: {}", e),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_collect_garbage_roots_every_live_thread() {
+        let mut vm = VirtualMachine::new();
+
+        // thread1: a no-op program, executed to completion. It has
+        // nothing left on its stack, but it's still a live `Thread`.
+        let code1 = vm.alloc(Code {
+            upvalues: 0,
+            params: 0,
+            constants: vec![],
+            instrs: vec![],
+            codes: vec![],
+        });
+        let thread1 = vm.load(code1);
+        assert!(matches!(
+            vm.execute(&thread1, None),
+            Ok(ExecResult::Finished)
+        ));
+
+        // thread2: yielded partway through, with a string on its stack
+        // that's reachable *only* through thread2 (not globals, and not
+        // thread1).
+        let hello = vm.alloc("hello".to_owned());
+        let code2 = vm.alloc(Code {
+            upvalues: 0,
+            params: 0,
+            constants: vec![Value::String(hello)],
+            instrs: vec![Instruction::LoadConstant as u8, 0],
+            codes: vec![],
+        });
+        let thread2 = vm.load(code2);
+        assert!(matches!(
+            vm.execute(&thread2, Some(1)),
+            Ok(ExecResult::Yielded)
+        ));
+
+        // A collection driven from thread1's side must not sweep
+        // thread2's still-live stack value out from under it.
+        vm.collect_garbage();
+
+        let thread2 = thread2.borrow();
+        match thread2.stack.as_slice() {
+            [Value::String(s)] => assert_eq!(**s, "hello"),
+            _ => panic!("expected a single string on thread2's stack"),
+        }
+    }
+
+    #[test]
+    fn test_table_getattr_setattr_and_method_call() {
+        let mut vm = VirtualMachine::new();
+
+        // The "method": an empty function, called via an attribute
+        // looked up on an object.
+        let method_code = vm.alloc(Code {
+            upvalues: 0,
+            params: 0,
+            constants: vec![],
+            instrs: vec![],
+            codes: vec![],
+        });
+        let method = vm.alloc(Function {
+            code: method_code,
+            upvalues: vec![],
+        });
+
+        let table = vm.alloc(Table::new());
+        let greet_str = vm.alloc("greet".to_owned());
+
+        let code = vm.alloc(Code {
+            upvalues: 0,
+            params: 0,
+            constants: vec![
+                Value::Object(table.clone()),
+                Value::String(greet_str),
+                Value::Function(method),
+            ],
+            instrs: vec![
+                // obj.greet = method
+                Instruction::LoadConstant as u8,
+                0,
+                Instruction::LoadConstant as u8,
+                1,
+                Instruction::LoadConstant as u8,
+                2,
+                Instruction::SetAttr as u8,
+                // obj.greet()
+                Instruction::LoadConstant as u8,
+                0,
+                Instruction::LoadConstant as u8,
+                1,
+                Instruction::GetAttr as u8,
+                Instruction::Call as u8,
+                0,
+            ],
+            codes: vec![],
+        });
+
+        let thread = vm.load(code);
+        let result = vm.execute(&thread, None);
+        assert!(matches!(result, Ok(ExecResult::Finished)));
+
+        // Call doesn't pop the callee; real bytecode would follow it
+        // with an explicit Pop.
+        assert_eq!(thread.borrow().stack.len(), 1);
+        assert!(table.get(&"greet".to_owned()).is_some());
+    }
+
+    #[test]
+    fn test_register_fn_called_through_instruction_call() {
+        let mut vm = VirtualMachine::new();
+        vm.register_fn("add_one", |x: i32| x + 1);
+
+        let name = vm.alloc("add_one".to_owned());
+        let code = vm.alloc(Code {
+            upvalues: 0,
+            params: 0,
+            constants: vec![Value::String(name), Value::Integer(41)],
+            instrs: vec![
+                Instruction::LoadGlobal as u8,
+                0, // "add_one"
+                Instruction::LoadConstant as u8,
+                1, // 41
+                Instruction::Call as u8,
+                1, // 1 argument
+            ],
+            codes: vec![],
+        });
+
+        let thread = vm.load(code);
+        assert!(matches!(
+            vm.execute(&thread, None),
+            Ok(ExecResult::Finished)
+        ));
+
+        // Native calls don't push a return-address frame, so the result
+        // replaces the callee and argument directly.
+        let thread = thread.borrow();
+        match thread.stack.as_slice() {
+            [Value::Integer(42)] => {}
+            _ => panic!("expected a single Integer(42) on the stack"),
+        }
+    }
+
+    #[test]
+    fn test_register_fn_underflow_returns_stack_empty() {
+        // `add` expects two arguments, but the call site only supplies
+        // one: `impl_into_native`'s marshalling must catch the missing
+        // second argument itself and return StackEmpty, rather than
+        // underflowing the host stack.
+        let mut vm = VirtualMachine::new();
+        vm.register_fn("add", |a: i32, b: i32| a + b);
+
+        let name = vm.alloc("add".to_owned());
+        let code = vm.alloc(Code {
+            upvalues: 0,
+            params: 0,
+            constants: vec![Value::String(name), Value::Integer(1)],
+            instrs: vec![
+                Instruction::LoadGlobal as u8,
+                0, // "add"
+                Instruction::LoadConstant as u8,
+                1, // 1
+                Instruction::Call as u8,
+                1, // only 1 of 2 expected arguments
+            ],
+            codes: vec![],
+        });
+
+        let thread = vm.load(code);
+        assert!(matches!(
+            vm.execute(&thread, None),
+            Err(ExecError::StackEmpty)
+        ));
+    }
+
+    #[test]
+    fn test_truncated_instruction_stream_does_not_panic() {
+        // LoadConstant takes a ConstantIndex operand that's missing here:
+        // the stream ends right after the opcode byte.
+        let code = Code {
+            upvalues: 0,
+            params: 0,
+            constants: vec![],
+            instrs: vec![Instruction::LoadConstant as u8],
+            codes: vec![],
+        };
+
+        assert_eq!(
+            disassemble(&code),
+            "0000 LoadConstant <truncated>\n"
+        );
+
+        let mut vm = VirtualMachine::new();
+        let code = vm.alloc(code);
+        let thread = vm.load(code);
+        assert!(matches!(
+            vm.execute(&thread, None),
+            Err(ExecError::InvalidInstruction)
+        ));
+    }
+
+    #[test]
+    fn test_load_global_reads_set_global_and_defaults_to_nil() {
+        let mut vm = VirtualMachine::new();
+
+        let name = vm.alloc("x".to_owned());
+        let missing_name = vm.alloc("missing".to_owned());
+        let code = vm.alloc(Code {
+            upvalues: 0,
+            params: 0,
+            constants: vec![
+                Value::String(name),
+                Value::Integer(7),
+                Value::String(missing_name),
+            ],
+            instrs: vec![
+                // x = 7
+                Instruction::LoadConstant as u8,
+                1, // 7
+                Instruction::SetGlobal as u8,
+                0, // "x"
+                // push x, then the value of an unset global
+                Instruction::LoadGlobal as u8,
+                0, // "x"
+                Instruction::LoadGlobal as u8,
+                2, // "missing"
+            ],
+            codes: vec![],
+        });
+
+        let thread = vm.load(code);
+        assert!(matches!(
+            vm.execute(&thread, None),
+            Ok(ExecResult::Finished)
+        ));
+
+        let thread = thread.borrow();
+        match thread.stack.as_slice() {
+            [Value::Integer(7), Value::Nil] => {}
+            _ => panic!("expected [Integer(7), Nil] on the stack"),
+        }
+    }
+
+    #[test]
+    fn test_max_stack_depth_triggers_stack_full() {
+        let mut vm = VirtualMachine::new();
+        vm.set_max_stack_depth(1);
+
+        let code = vm.alloc(Code {
+            upvalues: 0,
+            params: 0,
+            constants: vec![Value::Integer(1), Value::Integer(2)],
+            instrs: vec![
+                Instruction::LoadConstant as u8,
+                0, // fits within the depth of 1
+                Instruction::LoadConstant as u8,
+                1, // exceeds it
+            ],
+            codes: vec![],
+        });
+
+        let thread = vm.load(code);
+        assert!(matches!(
+            vm.execute(&thread, None),
+            Err(ExecError::StackFull)
+        ));
+    }
+
+    #[test]
+    fn test_execute_resumes_a_yielded_thread_with_more_fuel() {
+        let mut vm = VirtualMachine::new();
+
+        let code = vm.alloc(Code {
+            upvalues: 0,
+            params: 0,
+            constants: vec![
+                Value::Integer(1),
+                Value::Integer(2),
+                Value::Integer(3),
+            ],
+            instrs: vec![
+                Instruction::LoadConstant as u8,
+                0,
+                Instruction::LoadConstant as u8,
+                1,
+                Instruction::LoadConstant as u8,
+                2,
+            ],
+            codes: vec![],
+        });
+        let thread = vm.load(code);
+
+        // Only enough fuel for the first LoadConstant.
+        assert!(matches!(
+            vm.execute(&thread, Some(1)),
+            Ok(ExecResult::Yielded)
+        ));
+        assert!(matches!(
+            thread.borrow().stack.as_slice(),
+            [Value::Integer(1)]
+        ));
+
+        // Resuming with more fuel continues from where it left off,
+        // rather than restarting the program.
+        assert!(matches!(
+            vm.execute(&thread, None),
+            Ok(ExecResult::Finished)
+        ));
+        assert!(matches!(
+            thread.borrow().stack.as_slice(),
+            [Value::Integer(1), Value::Integer(2), Value::Integer(3)]
+        ));
+    }
+
+    #[test]
+    fn test_compile_text_closure_captures_and_shares_upvalue() {
+        // Drives the same path a real caller would: compile_text's
+        // generated program, run through execute. It builds two
+        // closures over one shared upvalue cell and calls both, so this
+        // exercises MakeFunction's capture list together with
+        // SetUpvalue and LoadUpvalue end to end.
+        let mut vm = VirtualMachine::new();
+        let program = compile_text(std::io::empty(), &mut vm).unwrap();
+        let thread = vm.load(program);
+
+        assert!(matches!(
+            vm.execute(&thread, None),
+            Ok(ExecResult::Finished)
+        ));
+
+        match vm.globals.get("count") {
+            Some(Value::Integer(5)) => {}
+            _ => panic!("expected global \"count\" to be set to 5"),
+        }
+    }
+}